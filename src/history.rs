@@ -0,0 +1,105 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use git2::{BranchType, Oid, Repository};
+
+use crate::Error;
+
+/// A single commit visited while walking a branch's history.
+#[derive(Debug, Clone)]
+pub struct CommitNode {
+    pub oid: Oid,
+    pub parents: Vec<Oid>,
+}
+
+impl CommitNode {
+    pub fn is_merge(&self) -> bool {
+        self.parents.len() > 1
+    }
+}
+
+/// The commits reachable from a branch tip, in an order where every commit
+/// is emitted only after all of its children have already been emitted.
+///
+/// This is the Kahn-style dual of a normal topological sort: instead of
+/// walking from roots towards tips, we walk from the tip towards the roots,
+/// so a commit's "in-degree" here is its number of not-yet-emitted children.
+/// Driving the traversal this way (rather than a revwalk with a merge
+/// "skip") is what lets octopus merges and back-to-back merges enumerate
+/// every parent instead of silently dropping commits.
+pub struct History {
+    nodes: Vec<CommitNode>,
+}
+
+impl History {
+    pub fn walk(repo: &Repository, branch: &str) -> Result<Self, Error> {
+        let tip = repo
+            .find_branch(branch, BranchType::Local)?
+            .get()
+            .target()
+            .ok_or_else(|| Error::BranchHasNoTarget(branch.to_owned()))?;
+
+        // First pass: discover every reachable commit and record its parents.
+        let mut parents_of: HashMap<Oid, Vec<Oid>> = HashMap::new();
+        let mut discovered: HashSet<Oid> = HashSet::new();
+        let mut frontier: VecDeque<Oid> = VecDeque::new();
+        frontier.push_back(tip);
+        discovered.insert(tip);
+        while let Some(oid) = frontier.pop_front() {
+            let commit = repo.find_commit(oid)?;
+            let parents: Vec<Oid> = commit.parent_ids().collect();
+            for &parent in &parents {
+                if discovered.insert(parent) {
+                    frontier.push_back(parent);
+                }
+            }
+            parents_of.insert(oid, parents);
+        }
+
+        // Build an in-degree map counting, for each commit, how many of its
+        // children are still pending emission.
+        let mut pending_children: HashMap<Oid, usize> =
+            parents_of.keys().map(|&oid| (oid, 0)).collect();
+        for parents in parents_of.values() {
+            for &parent in parents {
+                *pending_children.get_mut(&parent).unwrap() += 1;
+            }
+        }
+
+        // Kahn-style walk: a commit becomes ready once all of its children
+        // have been emitted, so we only ever emit a merge's parents after
+        // the merge itself (and anything else pointing at them) is done.
+        let mut queue: VecDeque<Oid> = pending_children
+            .iter()
+            .filter(|&(_, &count)| count == 0)
+            .map(|(&oid, _)| oid)
+            .collect();
+        let mut emitted: HashSet<Oid> = HashSet::new();
+        let mut nodes = Vec::with_capacity(parents_of.len());
+        while let Some(oid) = queue.pop_front() {
+            if !emitted.insert(oid) {
+                continue;
+            }
+            let parents = parents_of.remove(&oid).unwrap_or_default();
+            for &parent in &parents {
+                let remaining = pending_children.get_mut(&parent).unwrap();
+                *remaining -= 1;
+                if *remaining == 0 {
+                    queue.push_back(parent);
+                }
+            }
+            nodes.push(CommitNode { oid, parents });
+        }
+
+        Ok(Self { nodes })
+    }
+
+    /// All commits in emission order (tip-most first, roots last).
+    pub fn nodes(&self) -> &[CommitNode] {
+        &self.nodes
+    }
+
+    /// Just the merge commits, each with its full parent set.
+    pub fn merges(&self) -> impl Iterator<Item = &CommitNode> {
+        self.nodes.iter().filter(|node| node.is_merge())
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+
+use git2::{Commit, Oid, Repository};
+
+use crate::history::{CommitNode, History};
+use crate::Error;
+
+/// A signing key referenced by identity documents, signatures and
+/// attestations.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key(pub String);
+
+/// The well-known path at which a document commit's tree stores its
+/// `DocumentPayload`.
+pub const DOCUMENT_PATH: &str = "identity.json";
+
+/// The typed content of a document commit, serialized to a blob at
+/// `DOCUMENT_PATH` rather than inferred from the commit message.
+///
+/// `to_bytes`/`from_bytes` write and parse a fixed, hand-rolled JSON shape
+/// (`{"origin":"...","delegates":[...],"threshold":N}`) rather than going
+/// through a general-purpose serializer; this repo has no JSON crate as a
+/// dependency, and recipes only ever construct `Key`s from short,
+/// alphanumeric names (`k1`, `dev1`, ...). The parser is not a general JSON
+/// reader: it assumes exactly this field order, that `threshold` has no
+/// nested brackets, and that no key name contains a comma, colon, quote or
+/// brace — any of those would desynchronize the `find('[')`/`split(',')`
+/// scan below.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentPayload {
+    pub origin: Key,
+    pub delegates: Vec<Key>,
+    pub threshold: usize,
+}
+
+impl DocumentPayload {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let delegates = self
+            .delegates
+            .iter()
+            .map(|key| format!("\"{}\"", key.0))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"origin\":\"{}\",\"delegates\":[{delegates}],\"threshold\":{}}}",
+            self.origin.0, self.threshold,
+        )
+        .into_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?.trim();
+        let text = text.strip_prefix('{')?.strip_suffix('}')?;
+
+        let list_start = text.find('[')?;
+        let list_end = list_start + text[list_start..].find(']')?;
+        let delegates = parse_bracket_list(&text[list_start..=list_end]);
+
+        let mut origin = None;
+        let mut threshold = None;
+        let fields = format!("{}{}", &text[..list_start], &text[list_end + 1..]);
+        for field in fields.split(',') {
+            let (name, value) = field.split_once(':')?;
+            let value = value.trim().trim_matches('"');
+            match name.trim().trim_matches('"') {
+                "origin" => origin = Some(Key(value.to_owned())),
+                "threshold" => threshold = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            origin: origin?,
+            delegates,
+            threshold: threshold?,
+        })
+    }
+}
+
+/// The well-known path at which a signature commit's tree stores its
+/// `SignaturePayload`.
+pub const SIGNATURE_PATH: &str = "signature.json";
+
+/// The typed content of a signature commit: the key it claims to sign as.
+/// Verification still reads the signer back out of the `sig:<key>` commit
+/// message (the key is also the commit's author identity), so this payload
+/// exists to give signature commits real tree content of their own rather
+/// than falling back to the shared `simple_tree`, not to replace that
+/// message parsing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignaturePayload {
+    pub key: Key,
+}
+
+impl SignaturePayload {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!("{{\"key\":\"{}\"}}", self.key.0).into_bytes()
+    }
+}
+
+/// The well-known path at which an attestation commit's tree stores its
+/// `AttestationPayload`.
+pub const ATTESTATION_PATH: &str = "attestation.json";
+
+/// The typed content of an attestation commit: the keys whose signatures it
+/// refers to. As with [`SignaturePayload`], verification reads the
+/// referenced keys back out of the `attest:[...]` commit message; this
+/// payload just gives the commit real tree content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttestationPayload {
+    pub keys: Vec<Key>,
+}
+
+impl AttestationPayload {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let keys = self
+            .keys
+            .iter()
+            .map(|key| format!("\"{}\"", key.0))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{{\"keys\":[{keys}]}}").into_bytes()
+    }
+}
+
+/// One version of an identity document: the delegation set in effect from
+/// this commit onward, until superseded by a later document commit.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub delegates: Vec<Key>,
+    pub threshold: usize,
+}
+
+impl Document {
+    fn delegates_key(&self, key: &Key) -> bool {
+        self.delegates.contains(key)
+    }
+}
+
+/// Reads the `DocumentPayload` out of a document commit's tree, if it wrote
+/// one at `DOCUMENT_PATH`.
+fn read_document(repo: &Repository, commit: &Commit) -> Result<Option<Document>, Error> {
+    let tree = commit.tree()?;
+    let entry = match tree.get_path(Path::new(DOCUMENT_PATH)) {
+        Ok(entry) => entry,
+        Err(_) => return Ok(None),
+    };
+    let blob = repo.find_blob(entry.id())?;
+    Ok(
+        DocumentPayload::from_bytes(blob.content()).map(|payload| Document {
+            delegates: payload.delegates,
+            threshold: payload.threshold,
+        }),
+    )
+}
+
+/// The result of checking one attestation against the document/signature
+/// history reachable from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Valid,
+    MissingSignatures(Vec<Key>),
+    UnknownSigner(Key),
+    StaleDelegation,
+}
+
+enum Parsed {
+    DocumentMarker,
+    Signature(Key),
+    Attestation(Vec<Key>),
+    Other,
+}
+
+fn parse_bracket_list(rest: &str) -> Vec<Key> {
+    rest.trim()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .map(str::trim)
+        .map(|s| s.trim_matches('"'))
+        .filter(|s| !s.is_empty())
+        .map(|s| Key(s.to_owned()))
+        .collect()
+}
+
+/// Recognises the three shapes the `id-definition` recipe writes into
+/// commit messages: `doc<name>:[k1,k2]`, `sig:<key>` and `attest:[k1,k2]`.
+/// A document commit's message is just a human-readable label; its actual
+/// delegation set lives in the tree at `DOCUMENT_PATH` (see
+/// [`read_document`]).
+fn parse_message(message: &str) -> Parsed {
+    let message = message.trim();
+    if let Some(rest) = message.strip_prefix("attest:") {
+        Parsed::Attestation(parse_bracket_list(rest))
+    } else if let Some(key) = message.strip_prefix("sig:") {
+        Parsed::Signature(Key(key.trim().to_owned()))
+    } else if message.starts_with("doc") {
+        Parsed::DocumentMarker
+    } else {
+        Parsed::Other
+    }
+}
+
+fn message_of<'a>(commit: &'a Commit) -> &'a str {
+    commit.message().unwrap_or_default()
+}
+
+/// The delegation state reachable from one attestation commit: the keys of
+/// the signature commits reached before crossing a document commit, the
+/// document version in effect, and every older version behind it.
+struct DelegationContext {
+    signers: HashSet<Key>,
+    active_document: Option<Document>,
+    older_documents: Vec<Document>,
+}
+
+/// Walks back from an attestation commit through its parents, collecting
+/// every signature commit found before the first document commit on *that*
+/// path, and every document commit found (earlier delegation sets are used
+/// to tell an unknown signer apart from a stale one).
+///
+/// The cutoff is tracked per path, not per BFS level: each queued commit
+/// carries the `collecting` flag of the path that first reached it, cleared
+/// the moment that path crosses a document boundary. Candidate documents
+/// found while still collecting are resolved to the single active one
+/// afterwards by picking whichever is a descendant of the rest (older
+/// candidates fall back into `older_documents`), so a merge whose parents
+/// reach two document versions at the same depth does not settle on
+/// whichever one the frontier happened to see first.
+///
+/// Signatures are not bound to a document version: a signature commit made
+/// under an older document still counts for the active one when its path
+/// from the attestation crosses no document commit (in `id-definition`,
+/// `attest:[k2,k3]` reaches the `doc2` signatures through
+/// `attest:[k1,k2,k3]` and counts them).
+///
+/// Parent links are read from `nodes` — the same in-degree-resolved graph
+/// the [`History`] walker already built — rather than re-querying
+/// `Commit::parent_ids` here, so a merge's full parent set (what `merges()`
+/// and `is_merge()` expose) is exactly what this walk also sees.
+fn delegation_in_effect(
+    repo: &Repository,
+    nodes: &HashMap<Oid, &CommitNode>,
+    attestation_parents: &[Oid],
+) -> Result<DelegationContext, Error> {
+    let mut signers: HashSet<Key> = HashSet::new();
+    let mut candidates: Vec<(Oid, Document)> = Vec::new();
+    let mut older_documents: Vec<Document> = Vec::new();
+
+    let mut visited: HashSet<Oid> = HashSet::new();
+    let mut queue: VecDeque<(Oid, bool)> =
+        attestation_parents.iter().map(|&oid| (oid, true)).collect();
+    for &(oid, _) in &queue {
+        visited.insert(oid);
+    }
+
+    while let Some((oid, collecting)) = queue.pop_front() {
+        let commit = repo.find_commit(oid)?;
+        let parents = nodes.get(&oid).map(|node| node.parents.as_slice()).unwrap_or(&[]);
+        let still_collecting = match parse_message(message_of(&commit)) {
+            Parsed::DocumentMarker => {
+                if let Some(doc) = read_document(repo, &commit)? {
+                    if collecting {
+                        candidates.push((oid, doc));
+                    } else {
+                        older_documents.push(doc);
+                    }
+                }
+                // This path has now crossed a document boundary: anything
+                // further back belongs to an earlier delegation set.
+                false
+            }
+            Parsed::Signature(key) => {
+                if collecting {
+                    signers.insert(key);
+                }
+                collecting
+            }
+            Parsed::Attestation(_) | Parsed::Other => collecting,
+        };
+        for &parent in parents {
+            if visited.insert(parent) {
+                queue.push_back((parent, still_collecting));
+            }
+        }
+    }
+
+    // Multiple candidates can surface when different merge parents each
+    // reach their own nearest document first; since document commits are
+    // themselves linearly ordered, the active one is whichever candidate is
+    // a descendant of every other, with the rest demoted to older versions.
+    let mut active_document = None;
+    for (oid, doc) in candidates {
+        active_document = match active_document.take() {
+            None => Some((oid, doc)),
+            Some((best_oid, best_doc)) => {
+                if repo.graph_descendant_of(oid, best_oid)? {
+                    older_documents.push(best_doc);
+                    Some((oid, doc))
+                } else {
+                    older_documents.push(doc);
+                    Some((best_oid, best_doc))
+                }
+            }
+        };
+    }
+
+    Ok(DelegationContext {
+        signers,
+        active_document: active_document.map(|(_, doc)| doc),
+        older_documents,
+    })
+}
+
+/// Decides whether `attestation` is valid: every key it references must
+/// have signed (a `sig:` commit reachable before the active document) and
+/// be a member of the delegation set of the document version in effect at
+/// that point in history, and the referenced set must meet the document's
+/// threshold (default: all delegated keys).
+fn verify_attestation(
+    repo: &Repository,
+    nodes: &HashMap<Oid, &CommitNode>,
+    attestation: Oid,
+) -> Result<Verdict, Error> {
+    let commit = repo.find_commit(attestation)?;
+    let referenced = match parse_message(message_of(&commit)) {
+        Parsed::Attestation(keys) => keys,
+        _ => Vec::new(),
+    };
+    let attestation_parents: &[Oid] = nodes
+        .get(&attestation)
+        .map(|node| node.parents.as_slice())
+        .unwrap_or(&[]);
+
+    let DelegationContext {
+        signers,
+        active_document,
+        older_documents,
+    } = delegation_in_effect(repo, nodes, attestation_parents)?;
+
+    let document = match active_document {
+        Some(document) => document,
+        None => return Ok(Verdict::StaleDelegation),
+    };
+
+    for key in &referenced {
+        if document.delegates_key(key) {
+            continue;
+        }
+        if older_documents.iter().any(|doc| doc.delegates_key(key)) {
+            return Ok(Verdict::StaleDelegation);
+        }
+        return Ok(Verdict::UnknownSigner(key.clone()));
+    }
+
+    let unsigned: Vec<Key> = referenced
+        .iter()
+        .filter(|key| !signers.contains(key))
+        .cloned()
+        .collect();
+    if !unsigned.is_empty() {
+        return Ok(Verdict::MissingSignatures(unsigned));
+    }
+
+    let satisfied = document
+        .delegates
+        .iter()
+        .filter(|key| referenced.contains(key) && signers.contains(key))
+        .count();
+
+    if satisfied < document.threshold {
+        let missing = document
+            .delegates
+            .iter()
+            .filter(|key| !(referenced.contains(key) && signers.contains(key)))
+            .cloned()
+            .collect();
+        return Ok(Verdict::MissingSignatures(missing));
+    }
+
+    Ok(Verdict::Valid)
+}
+
+/// Verifies every attestation reachable from `branch`.
+///
+/// Attestation commits are, by construction, exactly the merge commits that
+/// fold a signature branch back into the identity document's branch, so the
+/// scan below walks `history.merges()` rather than every node; the full
+/// graph (`CommitNode::parents`) is still threaded into
+/// [`delegation_in_effect`] for each one.
+pub fn verify_branch(repo: &Repository, branch: &str) -> Result<HashMap<Oid, Verdict>, Error> {
+    let history = History::walk(repo, branch)?;
+    let nodes: HashMap<Oid, &CommitNode> =
+        history.nodes().iter().map(|node| (node.oid, node)).collect();
+
+    let mut results = HashMap::new();
+    for node in history.merges() {
+        let commit = repo.find_commit(node.oid)?;
+        if matches!(parse_message(message_of(&commit)), Parsed::Attestation(_)) {
+            results.insert(node.oid, verify_attestation(repo, &nodes, node.oid)?);
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RepoRecipe;
+
+    fn doc_bytes(delegates: &[&str], threshold: usize) -> Vec<u8> {
+        DocumentPayload {
+            origin: Key("origin".to_owned()),
+            delegates: delegates.iter().map(|k| Key((*k).to_owned())).collect(),
+            threshold,
+        }
+        .to_bytes()
+    }
+
+    fn only_verdict(repo: &RepoRecipe, branch: &str) -> Verdict {
+        let verdicts = verify_branch(&repo.repo().unwrap(), branch).unwrap();
+        assert_eq!(verdicts.len(), 1, "expected exactly one attestation: {verdicts:?}");
+        verdicts.into_values().next().unwrap()
+    }
+
+    #[test]
+    fn valid_when_every_delegate_signs() {
+        let repo = RepoRecipe::new("identity-test-valid", &|repo| {
+            repo.commit_with_tree("dev", "doc:[k1,k2]", &[], &[(DOCUMENT_PATH, &doc_bytes(&["k1", "k2"], 2))])?;
+            repo.commit("sigA", "sig:k1", &["dev"])?;
+            repo.commit("sigB", "sig:k2", &["dev"])?;
+            repo.commit("dev", "attest:[k1,k2]", &["sigA", "sigB"])?;
+            Ok(())
+        });
+        repo.create().unwrap();
+        assert_eq!(only_verdict(&repo, "dev"), Verdict::Valid);
+    }
+
+    #[test]
+    fn missing_signatures_when_a_delegate_never_signs() {
+        let repo = RepoRecipe::new("identity-test-missing", &|repo| {
+            repo.commit_with_tree("dev", "doc:[k1,k2]", &[], &[(DOCUMENT_PATH, &doc_bytes(&["k1", "k2"], 2))])?;
+            repo.commit("sigA", "sig:k1", &["dev"])?;
+            repo.commit("dev", "attest:[k1,k2]", &["sigA"])?;
+            Ok(())
+        });
+        repo.create().unwrap();
+        assert_eq!(
+            only_verdict(&repo, "dev"),
+            Verdict::MissingSignatures(vec![Key("k2".to_owned())])
+        );
+    }
+
+    #[test]
+    fn unknown_signer_when_key_is_never_delegated() {
+        let repo = RepoRecipe::new("identity-test-unknown", &|repo| {
+            repo.commit_with_tree("dev", "doc:[k1]", &[], &[(DOCUMENT_PATH, &doc_bytes(&["k1"], 1))])?;
+            repo.commit("sigA", "sig:k1", &["dev"])?;
+            repo.commit("dev", "attest:[k9]", &["sigA"])?;
+            Ok(())
+        });
+        repo.create().unwrap();
+        assert_eq!(
+            only_verdict(&repo, "dev"),
+            Verdict::UnknownSigner(Key("k9".to_owned()))
+        );
+    }
+
+    #[test]
+    fn stale_delegation_when_key_only_belonged_to_a_superseded_document() {
+        let repo = RepoRecipe::new("identity-test-stale", &|repo| {
+            repo.commit_with_tree("dev", "doc1:[k1]", &[], &[(DOCUMENT_PATH, &doc_bytes(&["k1"], 1))])?;
+            repo.commit("sigA", "sig:k1", &["dev"])?;
+            repo.commit("dev", "attest:[k1]", &["sigA"])?; // valid under doc1, not under test
+            repo.commit_with_tree("dev", "doc2:[k2]", &[], &[(DOCUMENT_PATH, &doc_bytes(&["k2"], 1))])?;
+            repo.commit("sigB", "sig:k2", &["dev"])?;
+            // k1 was only ever delegated under the now-superseded doc1.
+            repo.commit("dev", "attest:[k1]", &["sigB"])?;
+            Ok(())
+        });
+        repo.create().unwrap();
+        let verdicts = verify_branch(&repo.repo().unwrap(), "dev").unwrap();
+        assert_eq!(verdicts.len(), 2);
+        assert!(verdicts.values().any(|v| *v == Verdict::StaleDelegation));
+    }
+
+    /// Regresses the bug where the active document was picked by BFS level
+    /// rather than per path: here two distinct document versions are both
+    /// reachable at the same depth (one hop behind a signature each), so a
+    /// level-based cutoff would nondeterministically accept whichever
+    /// document its iteration order saw first instead of the one that is
+    /// actually a descendant of the other.
+    #[test]
+    fn equal_depth_documents_resolve_to_the_most_recent_one() {
+        let repo = RepoRecipe::new("identity-test-equal-depth", &|repo| {
+            repo.commit_with_tree(
+                "docA",
+                "docA:[k1,k2]",
+                &[],
+                &[(DOCUMENT_PATH, &doc_bytes(&["k1", "k2"], 2))],
+            )?;
+            repo.commit("sigB", "sig:k2", &["docA"])?;
+            repo.commit("docA", "sig:k1", &[])?;
+            repo.commit_with_tree(
+                "docC",
+                "docC:[k2,k3]",
+                &["docA"],
+                &[(DOCUMENT_PATH, &doc_bytes(&["k2", "k3"], 2))],
+            )?;
+            repo.commit("docC", "sig:k3", &[])?;
+            repo.commit("sigB", "attest:[k2,k3]", &["docC"])?;
+            Ok(())
+        });
+        repo.create().unwrap();
+        assert_eq!(only_verdict(&repo, "sigB"), Verdict::Valid);
+    }
+}
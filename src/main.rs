@@ -1,7 +1,13 @@
-use git2::{BranchType, Oid, Repository};
+use git2::{BranchType, Commit, Oid, Repository};
+use std::collections::BTreeMap;
 use std::fs::{create_dir_all, remove_dir_all};
 use thiserror::Error;
 
+mod history;
+mod identity;
+
+use history::History;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
@@ -9,6 +15,44 @@ pub enum Error {
 
     #[error(transparent)]
     Io(#[from] std::io::Error),
+
+    #[error("branch `{0}` has no target")]
+    BranchHasNoTarget(String),
+
+    #[error("merge conflict in {0:?}")]
+    MergeConflict(Vec<String>),
+
+    #[error("invalid signer `{name} <{email}>`: {source}")]
+    InvalidSigner {
+        name: String,
+        email: String,
+        #[source]
+        source: git2::Error,
+    },
+}
+
+/// An explicit author/committer identity and timestamp for a commit, used
+/// in place of the ambient `git config` user so that e.g. `dev1` and `k1`
+/// show up as distinct identities in the object data. `time` is a Unix
+/// timestamp and may be negative (times before the epoch are valid git
+/// commit times); `offset_minutes` is the timezone offset to record
+/// alongside it.
+struct Signer<'a> {
+    name: &'a str,
+    email: &'a str,
+    time: i64,
+    offset_minutes: i32,
+}
+
+impl<'a> Signer<'a> {
+    fn new(name: &'a str, email: &'a str, time: i64) -> Self {
+        Self {
+            name,
+            email,
+            time,
+            offset_minutes: 0,
+        }
+    }
 }
 
 struct RepoRecipe {
@@ -51,21 +95,115 @@ impl RepoRecipe {
         format!("repos/{}", self.name())
     }
 
-    fn tree(&self, entry: &str, data: &str) -> Result<Oid, Error> {
+    /// Walks the full history reachable from `branch`, correctly handling
+    /// the multi-parent merges produced by `commit`.
+    pub fn history(&self, branch: &str) -> Result<History, Error> {
+        History::walk(&self.repo()?, branch)
+    }
+
+    /// Verifies every identity attestation reachable from `branch` against
+    /// the delegation set in effect at the time it was made.
+    pub fn verify_identities(
+        &self,
+        branch: &str,
+    ) -> Result<std::collections::HashMap<Oid, identity::Verdict>, Error> {
+        identity::verify_branch(&self.repo()?, branch)
+    }
+
+    /// Builds a tree out of `(path, bytes)` entries, creating nested
+    /// directories for any path containing a `/` and writing each sub-tree
+    /// bottom-up before referencing it from its parent.
+    fn tree(&self, entries: &[(&str, &[u8])]) -> Result<Oid, Error> {
         let repo = self.repo()?;
-        let mut tree = repo.treebuilder(None)?;
-        tree.insert(entry.to_string(), repo.blob(data.as_bytes())?, 0o100644)?;
-        Ok(tree.write()?)
+        Self::write_tree(&repo, entries)
+    }
+
+    fn write_tree(repo: &Repository, entries: &[(&str, &[u8])]) -> Result<Oid, Error> {
+        let mut direct = Vec::new();
+        let mut subdirs: BTreeMap<&str, Vec<(&str, &[u8])>> = BTreeMap::new();
+        for &(path, data) in entries {
+            match path.split_once('/') {
+                Some((dir, rest)) => subdirs.entry(dir).or_default().push((rest, data)),
+                None => direct.push((path, data)),
+            }
+        }
+
+        let mut builder = repo.treebuilder(None)?;
+        for (path, data) in direct {
+            builder.insert(path, repo.blob(data)?, 0o100644)?;
+        }
+        for (dir, sub_entries) in subdirs {
+            let sub_tree = Self::write_tree(repo, &sub_entries)?;
+            builder.insert(dir, sub_tree, 0o040000)?;
+        }
+        Ok(builder.write()?)
     }
 
     fn simple_tree(&self) -> Result<Oid, Error> {
-        self.tree("data.txt", "text")
+        self.tree(&[("data.txt", b"text")])
     }
 
     fn commit(&self, branch: &str, message: &str, merges: &[&str]) -> Result<Oid, Error> {
+        self.commit_with_tree(branch, message, merges, &[])
+    }
+
+    /// Like `commit`, but attaches a structured payload (e.g. a serialized
+    /// identity document) to the commit's tree instead of the shared
+    /// `simple_tree`. `merges` only shapes the parent list here; the tree is
+    /// always fabricated from `entries`, regardless of what the parents
+    /// contain. Use `commit_merge` when the merge itself should be real.
+    fn commit_with_tree(
+        &self,
+        branch: &str,
+        message: &str,
+        merges: &[&str],
+        entries: &[(&str, &[u8])],
+    ) -> Result<Oid, Error> {
+        let tree_oid = self.entries_tree(entries)?;
+        self.commit_with_tree_oid(branch, message, merges, tree_oid, None)
+    }
+
+    /// Like `commit_with_tree`, but authored/committed as `signer`.
+    fn commit_with_tree_as(
+        &self,
+        branch: &str,
+        message: &str,
+        merges: &[&str],
+        entries: &[(&str, &[u8])],
+        signer: &Signer,
+    ) -> Result<Oid, Error> {
+        let tree_oid = self.entries_tree(entries)?;
+        self.commit_with_tree_oid(branch, message, merges, tree_oid, Some(signer))
+    }
+
+    fn entries_tree(&self, entries: &[(&str, &[u8])]) -> Result<Oid, Error> {
+        if entries.is_empty() {
+            self.simple_tree()
+        } else {
+            self.tree(entries)
+        }
+    }
+
+    /// A merge commit whose tree is computed by actually merging the
+    /// parents' trees with git2's merge machinery, rather than fabricating
+    /// one. Octopus parents (more than two) are folded in sequentially,
+    /// each merged pairwise against the first parent, carrying the
+    /// intermediate tree forward. Conflicting paths surface as
+    /// `Error::MergeConflict` instead of being resolved, since there is no
+    /// side for a recipe to prefer automatically.
+    fn commit_merge(&self, branch: &str, message: &str, merges: &[&str]) -> Result<Oid, Error> {
         let repo = self.repo()?;
-        let git_sig = repo.signature()?;
-        let tree = repo.find_tree(self.simple_tree()?)?;
+        let parent_commits = self.resolve_parents(&repo, branch, merges)?;
+        let tree_oid = Self::merge_parent_trees(&repo, &parent_commits)?;
+        self.commit_with_tree_oid(branch, message, merges, tree_oid, None)
+    }
+
+    fn resolve_parents<'repo>(
+        &self,
+        repo: &'repo Repository,
+        branch: &str,
+        merges: &[&str],
+    ) -> Result<Vec<Commit<'repo>>, Error> {
         let mut parent_branches = Vec::new();
         repo.find_branch(branch, BranchType::Local)
             .map(|branch| parent_branches.push(branch))
@@ -76,10 +214,24 @@ impl RepoRecipe {
         let mut parent_commits = Vec::new();
         for branch in parent_branches {
             let target_oid = branch.get().target().unwrap();
-            let target_commit = repo.find_commit(target_oid)?;
-            parent_commits.push(target_commit);
+            parent_commits.push(repo.find_commit(target_oid)?);
         }
-        let parent_commits_refs: Vec<_> = parent_commits.iter().map(|c| c).collect();
+        Ok(parent_commits)
+    }
+
+    fn commit_with_tree_oid(
+        &self,
+        branch: &str,
+        message: &str,
+        merges: &[&str],
+        tree_oid: Oid,
+        signer: Option<&Signer>,
+    ) -> Result<Oid, Error> {
+        let repo = self.repo()?;
+        let git_sig = Self::signature_for(&repo, signer)?;
+        let tree = repo.find_tree(tree_oid)?;
+        let parent_commits = self.resolve_parents(&repo, branch, merges)?;
+        let parent_commits_refs: Vec<_> = parent_commits.iter().collect();
         let commit_oid = repo.commit(
             None,
             &git_sig,
@@ -92,6 +244,63 @@ impl RepoRecipe {
         repo.branch(branch, &commit, true)?;
         Ok(commit_oid)
     }
+
+    /// Builds the `git2::Signature` to stamp a commit with: `signer`'s
+    /// explicit identity and timestamp if given, otherwise the ambient
+    /// `git config` identity.
+    fn signature_for<'repo>(
+        repo: &'repo Repository,
+        signer: Option<&Signer>,
+    ) -> Result<git2::Signature<'repo>, Error> {
+        match signer {
+            None => Ok(repo.signature()?),
+            Some(signer) => {
+                let time = git2::Time::new(signer.time, signer.offset_minutes);
+                git2::Signature::new(signer.name, signer.email, &time).map_err(|source| {
+                    Error::InvalidSigner {
+                        name: signer.name.to_owned(),
+                        email: signer.email.to_owned(),
+                        source,
+                    }
+                })
+            }
+        }
+    }
+
+    /// Merges `parents`' trees pairwise against the first parent, carrying
+    /// the intermediate result forward for octopus merges. Surfaces any
+    /// conflicting paths as `Error::MergeConflict` instead of resolving
+    /// them, since recipes have no way to pick a side.
+    fn merge_parent_trees<'repo>(
+        repo: &'repo Repository,
+        parents: &[Commit<'repo>],
+    ) -> Result<Oid, Error> {
+        let mut parents = parents.iter();
+        let anchor = parents
+            .next()
+            .expect("a merge commit has at least one parent");
+        let mut current_tree = anchor.tree()?;
+
+        for parent in parents {
+            let merge_base = repo.merge_base(anchor.id(), parent.id())?;
+            let ancestor_tree = repo.find_commit(merge_base)?.tree()?;
+            let mut index =
+                repo.merge_trees(&ancestor_tree, &current_tree, &parent.tree()?, None)?;
+            if index.has_conflicts() {
+                let conflicting_paths = index
+                    .conflicts()?
+                    .filter_map(|conflict| conflict.ok())
+                    .filter_map(|conflict| conflict.our.or(conflict.their).or(conflict.ancestor))
+                    .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+                    .collect();
+                return Err(Error::MergeConflict(conflicting_paths));
+            }
+            let merged_oid = index.write_tree_to(repo)?;
+            current_tree = repo.find_tree(merged_oid)?;
+        }
+
+        Ok(current_tree.id())
+    }
 }
 
 fn main() -> Result<(), Error> {
@@ -103,45 +312,168 @@ fn main() -> Result<(), Error> {
         repo.commit("b", "b1", &["bottom"])?;
         repo.commit("b", "b2", &[])?;
         repo.commit("b", "b3", &[])?;
-        repo.commit("top", "top", &["a", "b"])?;
+        repo.commit_merge("top", "top", &["a", "b"])?;
         Ok(())
     })
     .create()?;
 
-    RepoRecipe::new("id-definition", &|repo| {
+    // Unlike `long-diamond`, whose merge parents all carry the identical
+    // `simple_tree`, these two branches each edit the same path in
+    // incompatible ways, so `commit_merge` has to surface a real conflict
+    // instead of trivially resolving one.
+    RepoRecipe::new("conflicting-merge", &|repo| {
+        repo.commit_with_tree("base", "base", &[], &[("shared.txt", b"base")])?;
+        repo.commit_with_tree("left", "left edit", &["base"], &[("shared.txt", b"left")])?;
+        repo.commit_with_tree("right", "right edit", &["base"], &[("shared.txt", b"right")])?;
+        match repo.commit_merge("top", "merge", &["left", "right"]) {
+            Err(Error::MergeConflict(paths)) => {
+                assert_eq!(paths, vec!["shared.txt".to_owned()]);
+                Ok(())
+            }
+            Ok(_) => panic!("expected a conflict in shared.txt, got a clean merge"),
+            Err(other) => Err(other),
+        }
+    })
+    .create()?;
+
+    let id_definition = RepoRecipe::new("id-definition", &|repo| {
+        let key = |name: &str| identity::Key(name.to_owned());
+        let write_doc = |repo: &RepoRecipe,
+                         branch: &str,
+                         message: &str,
+                         merges: &[&str],
+                         origin: &str,
+                         delegates: &[&str],
+                         signer: &Signer|
+         -> Result<Oid, Error> {
+            let payload = identity::DocumentPayload {
+                origin: key(origin),
+                delegates: delegates.iter().map(|k| key(k)).collect(),
+                threshold: delegates.len(),
+            };
+            let bytes = payload.to_bytes();
+            repo.commit_with_tree_as(
+                branch,
+                message,
+                merges,
+                &[(identity::DOCUMENT_PATH, bytes.as_slice())],
+                signer,
+            )
+        };
+        let write_sig = |repo: &RepoRecipe,
+                         branch: &str,
+                         message: &str,
+                         merges: &[&str],
+                         signing_key: &str,
+                         signer: &Signer|
+         -> Result<Oid, Error> {
+            let bytes = identity::SignaturePayload { key: key(signing_key) }.to_bytes();
+            repo.commit_with_tree_as(
+                branch,
+                message,
+                merges,
+                &[(identity::SIGNATURE_PATH, bytes.as_slice())],
+                signer,
+            )
+        };
+        let write_attest = |repo: &RepoRecipe,
+                            branch: &str,
+                            message: &str,
+                            merges: &[&str],
+                            referenced: &[&str],
+                            signer: &Signer|
+         -> Result<Oid, Error> {
+            let bytes = identity::AttestationPayload {
+                keys: referenced.iter().map(|k| key(k)).collect(),
+            }
+            .to_bytes();
+            repo.commit_with_tree_as(
+                branch,
+                message,
+                merges,
+                &[(identity::ATTESTATION_PATH, bytes.as_slice())],
+                signer,
+            )
+        };
+
+        // Each developer and each key holder commits as themselves, with an
+        // explicit timestamp rather than the ambient `git config` identity.
+        // dev1's clock is set before the Unix epoch, to exercise negative
+        // commit times.
+        let dev1 = Signer::new("dev1", "dev1@example.com", -3600);
+        let dev3 = Signer::new("dev3", "dev3@example.com", 1_000_000_200);
+        let k1 = Signer::new("k1", "k1@example.com", 1_000_000_300);
+        let k2 = Signer::new("k2", "k2@example.com", 1_000_000_400);
+        let k3 = Signer::new("k3", "k3@example.com", 1_000_000_500);
+
         // Id document 1 (origin: dev1, delegations: [k1, k2])
-        repo.commit("dev1", "doc1", &[])?;
+        write_doc(
+            repo,
+            "dev1",
+            "doc1:[k1,k2]",
+            &[],
+            "dev1",
+            &["k1", "k2"],
+            &dev1,
+        )?;
         // Id document 1 signed by k2
-        repo.commit("dev2", "doc1-k2", &["dev1"])?;
+        write_sig(repo, "dev2", "sig:k2", &["dev1"], "k2", &k2)?;
         // Id document 1 signed by k1
-        repo.commit("dev1", "doc1-k1", &[])?;
+        write_sig(repo, "dev1", "sig:k1", &[], "k1", &k1)?;
         // Id attestation 1 (refers to signatures by [k1, k2])
-        repo.commit("dev1", "id1", &["dev2"])?;
+        write_attest(repo, "dev1", "attest:[k1,k2]", &["dev2"], &["k1", "k2"], &dev1)?;
 
         // Id document 2 (origin: dev1, delegations: [k1, k2, k3])
-        repo.commit("dev1", "doc2", &[])?;
+        write_doc(
+            repo,
+            "dev1",
+            "doc2:[k1,k2,k3]",
+            &[],
+            "dev1",
+            &["k1", "k2", "k3"],
+            &dev1,
+        )?;
         // Id document 2 signed by k3
-        repo.commit("dev3", "doc2-k3", &["dev1"])?;
+        write_sig(repo, "dev3", "sig:k3", &["dev1"], "k3", &k3)?;
         // Id document 2 signed by k2
-        repo.commit("dev2", "doc2-k2", &["dev1"])?;
+        write_sig(repo, "dev2", "sig:k2", &["dev1"], "k2", &k2)?;
         // Id document 2 signed by k1
-        repo.commit("dev1", "doc2-k1", &[])?;
+        write_sig(repo, "dev1", "sig:k1", &[], "k1", &k1)?;
         // Id attestation 2 (refers to signatures by [k1, k2, k3])
-        repo.commit("dev1", "id2", &["dev2", "dev3"])?;
+        write_attest(
+            repo,
+            "dev1",
+            "attest:[k1,k2,k3]",
+            &["dev2", "dev3"],
+            &["k1", "k2", "k3"],
+            &dev1,
+        )?;
 
         // Id document 3 (origin: dev3, delegations: [k2, k3])
-        repo.commit("dev3", "doc3", &[])?;
+        write_doc(
+            repo,
+            "dev3",
+            "doc3:[k2,k3]",
+            &[],
+            "dev3",
+            &["k2", "k3"],
+            &dev3,
+        )?;
         // Id document 3 signed by k2
-        repo.commit("dev2", "doc3-k2", &["dev3"])?;
+        write_sig(repo, "dev2", "sig:k2", &["dev3"], "k2", &k2)?;
         // Id document 3 signed by k3
-        repo.commit("dev3", "doc3-k3", &[])?;
+        write_sig(repo, "dev3", "sig:k3", &[], "k3", &k3)?;
         // Id attestation 3 (refers to signatures by [k2, k3])
-        repo.commit("dev1", "id3", &["dev2"])?;
+        write_attest(repo, "dev1", "attest:[k2,k3]", &["dev2"], &["k2", "k3"], &dev3)?;
 
-        repo.commit("top", "top", &["dev1", "dev2", "dev3"])?;
+        repo.commit_merge("top", "top", &["dev1", "dev2", "dev3"])?;
         Ok(())
-    })
-    .create()?;
+    });
+    id_definition.create()?;
+
+    for (attestation, verdict) in id_definition.verify_identities("top")? {
+        println!("{attestation}: {verdict:?}");
+    }
 
     Ok(())
 }